@@ -0,0 +1,265 @@
+//! Stitches the six faces of a krpano cube into a single equirectangular (spherical) panorama.
+//!
+//! This is an opt-in post-processing step: by default each cube face is saved as its own flat
+//! image, the way it has always been. When [`EquirectangularOptions::enabled`] is set, the
+//! assembled faces are resampled into one `width x height` equirectangular image instead, which
+//! is the format most panorama viewers (and stitching tools) expect.
+
+use std::collections::HashMap;
+
+use image::{DynamicImage, GenericImageView, Rgba, RgbaImage};
+
+/// One of the six faces of a krpano cube, named the same way
+/// [`KrpanoLevel`](crate::krpano::krpano_metadata::KrpanoLevel) names them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum CubeFace { Left, Right, Up, Down, Front, Back }
+
+impl CubeFace {
+    pub const ALL: [CubeFace; 6] =
+        [CubeFace::Left, CubeFace::Right, CubeFace::Up, CubeFace::Down, CubeFace::Front, CubeFace::Back];
+
+    /// The name this face has always been saved under, e.g. `"Left"`.
+    pub fn name(self) -> &'static str {
+        match self {
+            CubeFace::Left => "Left",
+            CubeFace::Right => "Right",
+            CubeFace::Up => "Up",
+            CubeFace::Down => "Down",
+            CubeFace::Front => "Front",
+            CubeFace::Back => "Back",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Axis { X, Y, Z }
+
+/// How a face's `u, v` (in `[0, 1]`) map onto its stored image.
+///
+/// krpano's cube faces aren't necessarily stored the way OpenGL cubemaps expect: a face can be
+/// rotated by a quarter turn and/or mirrored. This is kept as data (rather than hard-coded into
+/// the sampling math) so callers can override it per-face to match whatever convention a given
+/// krpano tour actually used.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FaceOrientation {
+    /// Number of quarter turns to rotate the face clockwise before sampling.
+    pub quarter_turns: u8,
+    pub flip_horizontal: bool,
+    pub flip_vertical: bool,
+}
+
+impl Default for FaceOrientation {
+    fn default() -> Self {
+        FaceOrientation { quarter_turns: 0, flip_horizontal: false, flip_vertical: false }
+    }
+}
+
+impl FaceOrientation {
+    /// Applies the rotation and flips to a `(u, v)` pair in `[0, 1]`.
+    fn apply(&self, (u, v): (f64, f64)) -> (f64, f64) {
+        let (mut u, mut v) = match self.quarter_turns % 4 {
+            0 => (u, v),
+            1 => (v, 1.0 - u),
+            2 => (1.0 - u, 1.0 - v),
+            _ => (1.0 - v, u),
+        };
+        if self.flip_horizontal { u = 1.0 - u; }
+        if self.flip_vertical { v = 1.0 - v; }
+        (u, v)
+    }
+}
+
+/// Which world axis a face's outward normal points along, and the per-face sampling
+/// orientation to apply once that face has been picked.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct FaceMapping { axis: Axis, positive: bool, orientation: FaceOrientation }
+
+/// Per-face axis mapping and orientation, overridable to account for krpano configurations
+/// that don't follow the default layout.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CubeFaceConfig(HashMap<CubeFace, FaceMapping>);
+
+impl Default for CubeFaceConfig {
+    fn default() -> Self {
+        let identity = FaceOrientation::default();
+        let mapping = [
+            (CubeFace::Right, Axis::X, true),
+            (CubeFace::Left, Axis::X, false),
+            (CubeFace::Up, Axis::Y, true),
+            (CubeFace::Down, Axis::Y, false),
+            (CubeFace::Back, Axis::Z, true),
+            (CubeFace::Front, Axis::Z, false),
+        ];
+        CubeFaceConfig(mapping.into_iter()
+            .map(|(face, axis, positive)| (face, FaceMapping { axis, positive, orientation: identity }))
+            .collect())
+    }
+}
+
+impl CubeFaceConfig {
+    /// Overrides the sampling orientation for a single face, keeping its axis mapping.
+    pub fn with_orientation(mut self, face: CubeFace, orientation: FaceOrientation) -> Self {
+        if let Some(mapping) = self.0.get_mut(&face) {
+            mapping.orientation = orientation;
+        }
+        self
+    }
+
+    fn get(&self, face: CubeFace) -> FaceMapping {
+        self.0[&face]
+    }
+}
+
+/// Options controlling the opt-in equirectangular post-processing step.
+#[derive(Debug, Clone, PartialEq)]
+pub struct EquirectangularOptions {
+    pub enabled: bool,
+    pub width: u32,
+    pub height: u32,
+    pub faces: CubeFaceConfig,
+}
+
+impl Default for EquirectangularOptions {
+    fn default() -> Self {
+        EquirectangularOptions { enabled: false, width: 4096, height: 2048, faces: CubeFaceConfig::default() }
+    }
+}
+
+/// Picks the cube face a direction vector points into, and that face's local `(u, v)`
+/// coordinates in `[0, 1]`.
+fn direction_to_face(dir: (f64, f64, f64), config: &CubeFaceConfig) -> (CubeFace, (f64, f64)) {
+    let (x, y, z) = dir;
+    let (axis, magnitude) = [(Axis::X, x.abs()), (Axis::Y, y.abs()), (Axis::Z, z.abs())]
+        .into_iter()
+        .max_by(|a, b| a.1.partial_cmp(&b.1).unwrap())
+        .unwrap();
+    let positive = match axis {
+        Axis::X => x >= 0.0,
+        Axis::Y => y >= 0.0,
+        Axis::Z => z >= 0.0,
+    };
+    let face = *CubeFace::ALL.iter()
+        .find(|&&f| { let m = config.get(f); m.axis == axis && m.positive == positive })
+        .expect("CubeFaceConfig must map every axis direction to a face");
+    // Face-local coordinates: divide the other two components by the dominant one to land in
+    // [-1, 1], then remap to [0, 1].
+    let (a, b) = match axis {
+        Axis::X => (-z / x.abs() * x.signum(), -y / magnitude),
+        Axis::Y => (x / magnitude, z / y.abs() * y.signum()),
+        Axis::Z => (x / z.abs() * z.signum(), -y / magnitude),
+    };
+    let uv = ((a + 1.0) / 2.0, (b + 1.0) / 2.0);
+    (face, config.get(face).orientation.apply(uv))
+}
+
+/// Bilinearly samples `image` at normalized coordinates `(u, v)` in `[0, 1]`.
+fn bilinear_sample(image: &DynamicImage, (u, v): (f64, f64)) -> Rgba<u8> {
+    let (width, height) = image.dimensions();
+    let x = (u.clamp(0.0, 1.0) * (width - 1) as f64).max(0.0);
+    let y = (v.clamp(0.0, 1.0) * (height - 1) as f64).max(0.0);
+    let (x0, y0) = (x.floor() as u32, y.floor() as u32);
+    let (x1, y1) = ((x0 + 1).min(width - 1), (y0 + 1).min(height - 1));
+    let (fx, fy) = (x - x0 as f64, y - y0 as f64);
+    let lerp = |a: u8, b: u8, t: f64| (a as f64 + (b as f64 - a as f64) * t).round() as u8;
+    let mix = |c00: Rgba<u8>, c10: Rgba<u8>, c01: Rgba<u8>, c11: Rgba<u8>| {
+        let top = [lerp(c00[0], c10[0], fx), lerp(c00[1], c10[1], fx), lerp(c00[2], c10[2], fx), lerp(c00[3], c10[3], fx)];
+        let bottom = [lerp(c01[0], c11[0], fx), lerp(c01[1], c11[1], fx), lerp(c01[2], c11[2], fx), lerp(c01[3], c11[3], fx)];
+        Rgba([
+            lerp(top[0], bottom[0], fy), lerp(top[1], bottom[1], fy),
+            lerp(top[2], bottom[2], fy), lerp(top[3], bottom[3], fy),
+        ])
+    };
+    mix(image.get_pixel(x0, y0), image.get_pixel(x1, y0), image.get_pixel(x0, y1), image.get_pixel(x1, y1))
+}
+
+/// Stitches six assembled cube faces into one equirectangular panorama.
+pub fn to_equirectangular(
+    faces: &HashMap<CubeFace, DynamicImage>,
+    options: &EquirectangularOptions,
+) -> RgbaImage {
+    let (width, height) = (options.width, options.height);
+    let mut out = RgbaImage::new(width, height);
+    for v_px in 0..height {
+        for u_px in 0..width {
+            let theta = (u_px as f64 / width as f64) * 2.0 * std::f64::consts::PI - std::f64::consts::PI;
+            let phi = std::f64::consts::FRAC_PI_2 - (v_px as f64 / height as f64) * std::f64::consts::PI;
+            let dir = (phi.cos() * theta.sin(), phi.sin(), phi.cos() * theta.cos());
+            let (face, uv) = direction_to_face(dir, &options.faces);
+            if let Some(image) = faces.get(&face) {
+                out.put_pixel(u_px, v_px, bilinear_sample(image, uv));
+            }
+        }
+    }
+    out
+}
+
+/// Decides what to actually save once all six cube faces have been downloaded and assembled: the
+/// six faces unchanged (the default), or a single stitched equirectangular panorama if the user
+/// opted into [`EquirectangularOptions::enabled`].
+///
+/// This is the gate [`crate::krpano::download_cube_faces`] calls instead of always writing out
+/// `faces` one by one, so the stitching above is actually reachable.
+pub fn finalize_cube_faces(
+    faces: HashMap<CubeFace, DynamicImage>,
+    options: &EquirectangularOptions,
+) -> Vec<(&'static str, DynamicImage)> {
+    if !options.enabled {
+        return faces.into_iter().map(|(face, image)| (face.name(), image)).collect();
+    }
+    vec![("equirectangular", DynamicImage::ImageRgba8(to_equirectangular(&faces, options)))]
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn direction_to_face_picks_dominant_axis() {
+        let config = CubeFaceConfig::default();
+        let (face, uv) = direction_to_face((0.0, 0.0, -1.0), &config);
+        assert_eq!(face, CubeFace::Front);
+        assert_eq!(uv, (0.5, 0.5));
+    }
+
+    #[test]
+    fn face_orientation_rotates_and_flips() {
+        let orientation = FaceOrientation { quarter_turns: 1, flip_horizontal: true, flip_vertical: false };
+        assert_eq!(orientation.apply((0.0, 0.0)), (1.0, 1.0));
+    }
+
+    fn sample_faces() -> HashMap<CubeFace, DynamicImage> {
+        CubeFace::ALL.into_iter()
+            .map(|face| (face, DynamicImage::ImageRgba8(RgbaImage::new(2, 2))))
+            .collect()
+    }
+
+    #[test]
+    fn finalize_cube_faces_keeps_six_faces_by_default() {
+        let options = EquirectangularOptions::default();
+        let out = finalize_cube_faces(sample_faces(), &options);
+        assert_eq!(out.len(), 6);
+        assert!(out.iter().any(|(name, _)| *name == "Front"));
+    }
+
+    #[test]
+    fn finalize_cube_faces_stitches_when_enabled() {
+        let mut options = EquirectangularOptions::default();
+        options.enabled = true;
+        options.width = 4;
+        options.height = 2;
+        let out = finalize_cube_faces(sample_faces(), &options);
+        assert_eq!(out.len(), 1);
+        assert_eq!(out[0].0, "equirectangular");
+    }
+
+    #[test]
+    fn bilinear_sample_averages_neighbours() {
+        let mut image = image::RgbaImage::new(2, 2);
+        image.put_pixel(0, 0, Rgba([0, 0, 0, 255]));
+        image.put_pixel(1, 0, Rgba([255, 0, 0, 255]));
+        image.put_pixel(0, 1, Rgba([0, 0, 0, 255]));
+        image.put_pixel(1, 1, Rgba([255, 0, 0, 255]));
+        let sampled = bilinear_sample(&DynamicImage::ImageRgba8(image), (0.5, 0.0));
+        assert_eq!(sampled, Rgba([128, 0, 0, 255]));
+    }
+}
@@ -1,15 +1,132 @@
+use std::collections::HashSet;
+use std::future::Future;
+use std::pin::Pin;
 use std::str::FromStr;
 use std::sync::Arc;
 
 use serde::{de, Deserialize, Deserializer};
 
+use crate::network::reqwest_client;
 use crate::Vec2d;
 
-#[derive(Debug, Deserialize, PartialEq)]
+/// Maximum number of nested `<include>` levels that are followed before giving up.
+///
+/// Real-world krpano tours rarely nest includes more than once or twice; this cap only exists
+/// to stop self-referential (or mutually-referential) includes from recursing forever.
+const MAX_INCLUDE_DEPTH: u32 = 16;
+
+#[derive(Debug, Deserialize, PartialEq, Default)]
 pub struct KrpanoMetadata {
+    #[serde(default, rename = "include")]
+    pub includes: Vec<Include>,
+    #[serde(default)]
     pub image: Vec<KrpanoImage>,
 }
 
+#[derive(Debug, Deserialize, PartialEq)]
+pub struct Include {
+    pub url: String,
+}
+
+/// Fetches the body of a document reachable at `url`.
+///
+/// Pulled out behind a trait (rather than calling [`reqwest_client`] directly from
+/// [`KrpanoMetadata::merge_includes`]) purely so tests can exercise the recursive merge, cycle
+/// detection, and depth cap without making real HTTP requests; [`HttpFetcher`] is what
+/// production code actually uses.
+trait IncludeFetcher {
+    fn fetch<'a>(&'a self, url: &'a str) -> Pin<Box<dyn Future<Output=Result<String, String>> + 'a>>;
+}
+
+struct HttpFetcher;
+
+impl IncludeFetcher for HttpFetcher {
+    fn fetch<'a>(&'a self, url: &'a str) -> Pin<Box<dyn Future<Output=Result<String, String>> + 'a>> {
+        Box::pin(async move {
+            reqwest_client()
+                .get(url)
+                .send().await
+                .map_err(|e| format!("could not fetch include '{}': {}", url, e))?
+                .text().await
+                .map_err(|e| format!("could not read include '{}': {}", url, e))
+        })
+    }
+}
+
+impl KrpanoMetadata {
+    /// Recursively fetches every `<include url="…">` reachable from this document (resolving
+    /// each URL relative to the document it was found in) and merges their `image` contents into
+    /// this one, so that the caller only has to deal with a single, fully-resolved document.
+    ///
+    /// Cyclical includes (an include that, directly or transitively, refers back to a document
+    /// that is already being resolved) are skipped rather than followed, and resolution gives up
+    /// after [`MAX_INCLUDE_DEPTH`] levels of nesting. An include that fails to fetch or doesn't
+    /// parse as krpano XML (e.g. `skin/flatpano_setup.xml`, a UI-skin config rather than image
+    /// data) is logged and skipped rather than failing the whole document — before this method
+    /// existed, such includes were silently ignored, and a single bad include shouldn't turn a
+    /// previously-working (if incomplete) dezoom into a hard failure.
+    pub async fn resolve_includes(mut self, base_url: &str) -> Result<Self, String> {
+        let mut seen = HashSet::new();
+        seen.insert(base_url.to_string());
+        self.merge_includes(base_url, &mut seen, 0, &HttpFetcher).await;
+        Ok(self)
+    }
+
+    // Async fns can't recurse directly (the compiler would need an infinitely-sized future to
+    // represent it), so the recursive call below is boxed to give it a fixed-size, heap-allocated
+    // future instead.
+    fn merge_includes<'a>(
+        &'a mut self,
+        base_url: &'a str,
+        seen: &'a mut HashSet<String>,
+        depth: u32,
+        fetcher: &'a dyn IncludeFetcher,
+    ) -> Pin<Box<dyn Future<Output=()> + 'a>> {
+        Box::pin(async move {
+            if self.includes.is_empty() {
+                return;
+            }
+            if depth >= MAX_INCLUDE_DEPTH {
+                eprintln!(
+                    "warning: krpano include recursion exceeded {} levels while resolving '{}', giving up",
+                    MAX_INCLUDE_DEPTH, base_url
+                );
+                return;
+            }
+            let includes = std::mem::take(&mut self.includes);
+            for include in includes {
+                let url = resolve_relative_url(base_url, &include.url);
+                if !seen.insert(url.clone()) {
+                    continue; // already resolved (or in progress): avoid looping forever
+                }
+                match fetch_include(&url, fetcher).await {
+                    Ok(mut included) => {
+                        included.merge_includes(&url, seen, depth + 1, fetcher).await;
+                        self.image.extend(included.image);
+                    }
+                    Err(e) => eprintln!("warning: ignoring krpano include '{}': {}", url, e),
+                }
+            }
+        })
+    }
+}
+
+async fn fetch_include(url: &str, fetcher: &dyn IncludeFetcher) -> Result<KrpanoMetadata, String> {
+    let body = fetcher.fetch(url).await?;
+    serde_xml_rs::from_str(&body).map_err(|e| format!("could not parse include '{}': {}", url, e))
+}
+
+/// Resolves a krpano `url="…"` attribute found in the document located at `base_url`.
+///
+/// krpano include URLs are always relative to the document that contains them, so this joins
+/// them the same way a browser would resolve a relative link.
+fn resolve_relative_url(base_url: &str, relative: &str) -> String {
+    match url::Url::parse(base_url).and_then(|base| base.join(relative)) {
+        Ok(joined) => joined.into(),
+        Err(_) => relative.to_string(),
+    }
+}
+
 #[derive(Debug, Deserialize, PartialEq)]
 pub struct KrpanoImage {
     pub tilesize: Option<u32>,
@@ -21,6 +138,17 @@ pub struct KrpanoImage {
 
 fn default_base_index() -> u32 { 1 }
 
+impl KrpanoImage {
+    /// Level descriptions for every shape in this image, with `%l` starting from
+    /// [`KrpanoImage::baseindex`] rather than always counting from `1`.
+    pub fn level_descriptions(self) -> Vec<Result<LevelDesc, &'static str>> {
+        let KrpanoImage { level, baseindex, .. } = self;
+        level.into_iter()
+            .flat_map(|level| level.level_descriptions(None, baseindex))
+            .collect()
+    }
+}
+
 pub struct LevelDesc {
     pub name: &'static str,
     pub size: Vec2d,
@@ -32,6 +160,10 @@ pub struct LevelDesc {
 pub struct ShapeDesc {
     url: TemplateString<TemplateVariable>,
     multires: Option<String>,
+    /// Number of frames in an object/frame-movie tileset, if any. Each frame gets its own
+    /// [`LevelDesc`], with the `%f` template variable substituted for its 1-based frame number.
+    #[serde(default)]
+    frames: Option<u32>,
 }
 
 #[derive(Deserialize, PartialEq, Debug)]
@@ -58,21 +190,27 @@ pub enum KrpanoLevel {
 }
 
 impl KrpanoLevel {
-    pub fn level_descriptions(self, size: Option<Vec2d>) -> Vec<Result<LevelDesc, &'static str>> {
+    /// Level descriptions for this shape (and, if it's a [`KrpanoLevel::Level`], its nested
+    /// shapes), with `%l` numbered from `base_index`.
+    ///
+    /// Crate-private: the only entry point meant for callers outside this module is
+    /// [`KrpanoImage::level_descriptions`], which supplies `base_index` from the document's own
+    /// `baseindex` attribute instead of leaving it to the caller to get right.
+    pub(crate) fn level_descriptions(self, size: Option<Vec2d>, base_index: u32) -> Vec<Result<LevelDesc, &'static str>> {
         match self {
             Self::Level(LevelAttributes { tiledimagewidth, tiledimageheight, shape }) => {
                 let size = Vec2d { x: tiledimagewidth, y: tiledimageheight };
-                shape.into_iter().flat_map(|level| level.level_descriptions(Some(size))).collect()
+                shape.into_iter().flat_map(|level| level.level_descriptions(Some(size), base_index)).collect()
             },
-            Self::Cube(d) => shape_descriptions("Cube", d, size),
-            Self::Cylinder(d) => shape_descriptions("Cylinder", d, size),
-            Self::Flat(d) => shape_descriptions("Flat", d, size),
-            Self::Left(d) => shape_descriptions("Left", d, size),
-            Self::Right(d) => shape_descriptions("Right", d, size),
-            Self::Front(d) => shape_descriptions("Front", d, size),
-            Self::Back(d) => shape_descriptions("Back", d, size),
-            Self::Up(d) => shape_descriptions("Up", d, size),
-            Self::Down(d) => shape_descriptions("Down", d, size),
+            Self::Cube(d) => shape_descriptions("Cube", d, size, base_index),
+            Self::Cylinder(d) => shape_descriptions("Cylinder", d, size, base_index),
+            Self::Flat(d) => shape_descriptions("Flat", d, size, base_index),
+            Self::Left(d) => shape_descriptions("Left", d, size, base_index),
+            Self::Right(d) => shape_descriptions("Right", d, size, base_index),
+            Self::Front(d) => shape_descriptions("Front", d, size, base_index),
+            Self::Back(d) => shape_descriptions("Back", d, size, base_index),
+            Self::Up(d) => shape_descriptions("Up", d, size, base_index),
+            Self::Down(d) => shape_descriptions("Down", d, size, base_index),
         }
     }
 }
@@ -81,23 +219,35 @@ fn shape_descriptions(
     name: &'static str,
     desc: ShapeDesc,
     size: Option<Vec2d>,
+    base_index: u32,
 ) -> Vec<Result<LevelDesc, &'static str>> {
-    let ShapeDesc { multires, url } = desc;
-    if let Some(multires) = multires {
-        parse_multires(&multires).into_iter().map(|result|
-            result.map(|(size, tilesize)| LevelDesc {
-                name,
-                size,
-                tilesize: Some(tilesize),
-                url: url.clone(),
-            })
-        ).collect()
+    let ShapeDesc { multires, url, frames } = desc;
+    // Each multires resolution tier is krpano's `%l` level: its position in the `multires` size
+    // list, counting from the image's `baseindex` rather than always from 1. A plain
+    // (non-multires) shape has only one resolution, so it is just `base_index` itself.
+    let levels: Vec<Result<(Vec2d, Option<Vec2d>), &'static str>> = if let Some(multires) = &multires {
+        parse_multires(multires).into_iter()
+            .map(|result| result.map(|(size, tilesize)| (size, Some(tilesize))))
+            .collect()
     } else if let Some(size) = size {
-        let tilesize = None;
-        vec![Ok(LevelDesc { name, size, tilesize, url })]
+        vec![Ok((size, None))]
     } else {
         vec![Err("missing multires attribute")]
-    }
+    };
+    let frame_count = frames.unwrap_or(1);
+    levels.into_iter().enumerate().flat_map(|(level_index, result)| {
+        let (size, tilesize) = match result {
+            Ok(ok) => ok,
+            Err(e) => return vec![Err(e)],
+        };
+        let level_url = url.with_value(TemplateVariable::Level, base_index + level_index as u32);
+        (1..=frame_count).map(|frame| Ok(LevelDesc {
+            name,
+            size,
+            tilesize,
+            url: level_url.with_value(TemplateVariable::Frame, frame),
+        })).collect()
+    }).collect()
 }
 
 /// Parse a multires string into a vector of (image size, tile_size)
@@ -150,6 +300,8 @@ impl FromStr for TemplateString<TemplateVariable> {
                 Some('h') | Some('x') | Some('u') | Some('c') => TemplateVariable::X,
                 Some('v') | Some('y') | Some('r') => TemplateVariable::Y,
                 Some('s') => TemplateVariable::Side,
+                Some('l') => TemplateVariable::Level,
+                Some('f') => TemplateVariable::Frame,
                 Some(x) => return Err(format!("Unknown template variable '{}' in '{}'", x, input)),
                 None => return Err(format!("Invalid templating syntax in '{}'", input))
             };
@@ -170,6 +322,14 @@ impl TemplateString<TemplateVariable> {
             self.0.iter().map(|part| part.with_side(side)).collect()
         )))
     }
+
+    /// Replaces every occurrence of `variable` with its concrete value, zero-padded to the
+    /// width the template requested (e.g. `%000l` pads to 3 digits). Used to burn the level and
+    /// frame numbers of a multi-frame/multires tileset into the URL once they're known, so only
+    /// the per-tile `X`/`Y`/`Side` variables are left for [`all_sides`](Self::all_sides).
+    fn with_value(&self, variable: TemplateVariable, value: u32) -> TemplateString<TemplateVariable> {
+        TemplateString(self.0.iter().map(|part| part.with_value(variable, value)).collect())
+    }
 }
 
 
@@ -189,14 +349,28 @@ impl TemplateStringPart<TemplateVariable> {
                     TemplateVariable::X => TemplateStringPart::Variable { padding, variable: XY::X },
                     TemplateVariable::Y => TemplateStringPart::Variable { padding, variable: XY::Y },
                     TemplateVariable::Side => TemplateStringPart::Literal(Arc::new(side[..1].to_string())),
+                    TemplateVariable::Level | TemplateVariable::Frame => panic!(
+                        "expected {:?} to already have been resolved to a literal via `with_value` \
+                         before `with_side` runs", variable
+                    ),
                 }
             }
         }
     }
+
+    /// Replaces this part with a zero-padded literal if it is a `variable` variable, leaving
+    /// literals and other variables untouched.
+    fn with_value(&self, variable: TemplateVariable, value: u32) -> TemplateStringPart<TemplateVariable> {
+        match self {
+            TemplateStringPart::Variable { padding, variable: v } if *v == variable =>
+                TemplateStringPart::Literal(Arc::new(format!("{:0width$}", value, width = *padding as usize))),
+            other => other.clone(),
+        }
+    }
 }
 
-#[derive(Debug, PartialEq, Clone)]
-pub enum TemplateVariable { X, Y, Side }
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum TemplateVariable { X, Y, Side, Level, Frame }
 
 #[derive(Debug, PartialEq, Clone)]
 pub enum XY { X, Y }
@@ -215,6 +389,112 @@ mod test {
 
     fn y(padding: u32) -> TemplateStringPart<TemplateVariable> { Variable { padding, variable: Y } }
 
+    /// Stands in for [`HttpFetcher`] in tests: serves canned bodies from a map instead of
+    /// making real HTTP requests, so the recursive merge/cycle-detection/depth-cap logic can be
+    /// exercised without a network.
+    struct FakeFetcher(std::collections::HashMap<String, String>);
+
+    impl<const N: usize> From<[(&'static str, &'static str); N]> for FakeFetcher {
+        fn from(pairs: [(&'static str, &'static str); N]) -> Self {
+            FakeFetcher(pairs.into_iter().map(|(k, v)| (k.to_string(), v.to_string())).collect())
+        }
+    }
+
+    impl IncludeFetcher for FakeFetcher {
+        fn fetch<'a>(&'a self, url: &'a str) -> Pin<Box<dyn Future<Output=Result<String, String>> + 'a>> {
+            Box::pin(async move {
+                self.0.get(url)
+                    .map(|body| body.to_string())
+                    .ok_or_else(|| format!("no fake body registered for '{}'", url))
+            })
+        }
+    }
+
+    fn krpano_image_count(metadata: &KrpanoMetadata) -> usize { metadata.image.len() }
+
+    #[tokio::test]
+    async fn merge_includes_pulls_in_included_images() {
+        let mut root = KrpanoMetadata {
+            includes: vec![Include { url: "child.xml".to_string() }],
+            image: vec![],
+        };
+        let fetcher = FakeFetcher::from([
+            ("http://example.com/child.xml", r#"<krpano><image><flat url="a.jpg"/></image></krpano>"#),
+        ]);
+        let mut seen = HashSet::new();
+        seen.insert("http://example.com/root.xml".to_string());
+        root.merge_includes("http://example.com/root.xml", &mut seen, 0, &fetcher).await;
+        assert_eq!(krpano_image_count(&root), 1);
+        assert!(root.includes.is_empty());
+    }
+
+    #[tokio::test]
+    async fn merge_includes_skips_cycles_instead_of_looping() {
+        // root -> a -> b -> a (cycle back to an include already being resolved)
+        let mut root = KrpanoMetadata {
+            includes: vec![Include { url: "a.xml".to_string() }],
+            image: vec![],
+        };
+        let fetcher = FakeFetcher::from([
+            ("http://example.com/a.xml", r#"<krpano><include url="b.xml"/><image><flat url="a.jpg"/></image></krpano>"#),
+            ("http://example.com/b.xml", r#"<krpano><include url="a.xml"/><image><flat url="b.jpg"/></image></krpano>"#),
+        ]);
+        let mut seen = HashSet::new();
+        seen.insert("http://example.com/root.xml".to_string());
+        root.merge_includes("http://example.com/root.xml", &mut seen, 0, &fetcher).await;
+        // Both a.xml and b.xml contribute their image once; the cyclical include back to a.xml is skipped.
+        assert_eq!(krpano_image_count(&root), 2);
+    }
+
+    #[tokio::test]
+    async fn merge_includes_gives_up_past_max_depth() {
+        let mut root = KrpanoMetadata {
+            includes: vec![Include { url: "self.xml".to_string() }],
+            image: vec![],
+        };
+        // self.xml includes a *different* URL each time (so cycle detection doesn't short-circuit
+        // it first), forcing resolution to recurse until the depth cap kicks in. None of these
+        // documents carry an <image>. Without the depth cap, resolution would follow the chain
+        // until the unregistered self{MAX_INCLUDE_DEPTH + 1}.xml, and `seen` would outgrow the
+        // bound asserted below.
+        let mut bodies: std::collections::HashMap<String, String> = (0..=MAX_INCLUDE_DEPTH).map(|i| (
+            format!("http://example.com/self{}.xml", i),
+            format!(r#"<krpano><include url="self{}.xml"/></krpano>"#, i + 1),
+        )).collect();
+        bodies.insert(
+            "http://example.com/self.xml".to_string(),
+            r#"<krpano><include url="self0.xml"/></krpano>"#.to_string(),
+        );
+        let fetcher = FakeFetcher(bodies);
+        let mut seen = HashSet::new();
+        seen.insert("http://example.com/root.xml".to_string());
+        root.merge_includes("http://example.com/root.xml", &mut seen, 0, &fetcher).await;
+        assert!(root.image.is_empty());
+        assert!(seen.len() as u32 <= MAX_INCLUDE_DEPTH + 2);
+    }
+
+    #[tokio::test]
+    async fn merge_includes_skips_includes_that_fail_to_fetch_or_parse() {
+        // One include 404s, the other isn't krpano XML at all (e.g. a UI-skin config like
+        // skin/flatpano_setup.xml) — neither should abort resolution of the sibling that's fine.
+        let mut root = KrpanoMetadata {
+            includes: vec![
+                Include { url: "missing.xml".to_string() },
+                Include { url: "skin/flatpano_setup.xml".to_string() },
+                Include { url: "child.xml".to_string() },
+            ],
+            image: vec![],
+        };
+        let fetcher = FakeFetcher::from([
+            ("http://example.com/skin/flatpano_setup.xml", "<not><valid krpano xml"),
+            ("http://example.com/child.xml", r#"<krpano><image><flat url="a.jpg"/></image></krpano>"#),
+        ]);
+        let mut seen = HashSet::new();
+        seen.insert("http://example.com/root.xml".to_string());
+        root.merge_includes("http://example.com/root.xml", &mut seen, 0, &fetcher).await;
+        assert_eq!(krpano_image_count(&root), 1);
+    }
+
     #[test]
     fn parse_xml_cylinder() {
         let parsed: KrpanoMetadata = serde_xml_rs::from_str(r#"
@@ -230,6 +510,7 @@ mod test {
         </krpano>
         "#).unwrap();
         assert_eq!(parsed, KrpanoMetadata {
+            includes: vec![Include { url: "skin/flatpano_setup.xml".to_string() }],
             image: vec![
                 KrpanoImage {
                     baseindex: 1,
@@ -244,6 +525,7 @@ mod test {
                                     y(0), str("_"), x(0), str(".jpg"),
                                 ]),
                                 multires: None,
+                                frames: None,
                             })],
                         }),
                     ],
@@ -275,6 +557,7 @@ mod test {
                                 str("https://example.com/"), y(3), str("/"),
                                 x(4), str(".jpg")]),
                             multires: None,
+                            frames: None,
                         })],
                 })],
             }]
@@ -296,6 +579,7 @@ mod test {
                 level: vec![KrpanoLevel::Flat(ShapeDesc {
                     url: TemplateString(vec![str("https://example.com/"), ]),
                     multires: Some("512,768x554,1664x1202,3200x2310,6400x4618,12800x9234".to_string()),
+                    frames: None,
                 })],
             }]
         })
@@ -309,4 +593,72 @@ mod test {
             Ok((Vec2d { x: 9, y: 1 }, Vec2d { x: 4, y: 4 })),
         ], parse_multires("3,6x7,8x8,9x1x4"))
     }
+
+    #[test]
+    fn parse_level_and_frame_variables() {
+        use TemplateVariable::{Level, Frame};
+        assert_eq!(
+            "tiles/%000l/%f.jpg".parse(),
+            Ok(TemplateString(vec![
+                str("tiles/"),
+                Variable { padding: 3, variable: Level },
+                str("/"),
+                Variable { padding: 0, variable: Frame },
+                str(".jpg"),
+            ]))
+        );
+    }
+
+    fn rendered_urls(desc: ShapeDesc, base_index: u32) -> Vec<String> {
+        shape_descriptions("Cube", desc, None, base_index).into_iter()
+            .map(|result| result.unwrap().url.0.into_iter().map(|part| match part {
+                Literal(s) => (*s).clone(),
+                Variable { .. } => panic!("expected every variable to be resolved to a literal"),
+            }).collect())
+            .collect()
+    }
+
+    #[test]
+    fn shape_descriptions_cross_product_of_levels_and_frames() {
+        let desc = ShapeDesc {
+            url: "tiles/l%l_f%f.jpg".parse().unwrap(),
+            multires: Some("512,100x100,200x200".to_string()),
+            frames: Some(2),
+        };
+        assert_eq!(rendered_urls(desc, 1), vec![
+            "tiles/l1_f1.jpg", "tiles/l1_f2.jpg",
+            "tiles/l2_f1.jpg", "tiles/l2_f2.jpg",
+        ]);
+    }
+
+    #[test]
+    fn shape_descriptions_level_counts_from_base_index() {
+        let desc = ShapeDesc {
+            url: "tiles/l%l.jpg".parse().unwrap(),
+            multires: Some("512,100x100,200x200".to_string()),
+            frames: None,
+        };
+        assert_eq!(rendered_urls(desc, 0), vec!["tiles/l0.jpg", "tiles/l1.jpg"]);
+    }
+
+    #[test]
+    fn image_level_descriptions_honors_baseindex_end_to_end() {
+        let parsed: KrpanoMetadata = serde_xml_rs::from_str(r#"
+        <krpano>
+        <image baseindex="0" tilesize="512">
+            <level tiledimagewidth="3280" tiledimageheight="3280">
+                <cube url="tiles/l%l.jpg" multires="512,100x100,200x200"/>
+            </level>
+        </image>
+        </krpano>"#).unwrap();
+        let urls: Vec<String> = parsed.image.into_iter().next().unwrap()
+            .level_descriptions().into_iter()
+            .map(|result| result.unwrap().url.0.into_iter().map(|part| match part {
+                Literal(s) => (*s).clone(),
+                Variable { .. } => panic!("expected every variable to be resolved to a literal"),
+            }).collect())
+            .collect();
+        // %l starts counting from the image's baseindex="0", not from 1.
+        assert_eq!(urls, vec!["tiles/l0.jpg", "tiles/l1.jpg"]);
+    }
 }
\ No newline at end of file
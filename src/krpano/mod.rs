@@ -0,0 +1,49 @@
+pub mod krpano_metadata;
+pub mod equirectangular;
+
+use std::collections::HashMap;
+
+use crate::network::reqwest_client;
+use equirectangular::{CubeFace, EquirectangularOptions, finalize_cube_faces};
+use krpano_metadata::{KrpanoImage, KrpanoMetadata, LevelDesc};
+
+/// Fetches and fully resolves the krpano tour document at `url`: downloads the top-level XML,
+/// follows every `<include>` it references ([`KrpanoMetadata::resolve_includes`]), and returns
+/// the level descriptions of its first `<image>` with `%l` numbered from that image's
+/// `baseindex` ([`KrpanoImage::level_descriptions`]) rather than always from `1`.
+pub async fn parse_tour(url: &str) -> Result<Vec<Result<LevelDesc, &'static str>>, String> {
+    let body = reqwest_client()
+        .get(url)
+        .send().await
+        .map_err(|e| format!("could not fetch krpano document '{}': {}", url, e))?
+        .text().await
+        .map_err(|e| format!("could not read krpano document '{}': {}", url, e))?;
+    let metadata: KrpanoMetadata = serde_xml_rs::from_str(&body)
+        .map_err(|e| format!("could not parse krpano document '{}': {}", url, e))?;
+    let metadata = metadata.resolve_includes(url).await?;
+    let image: KrpanoImage = metadata.image.into_iter().next()
+        .ok_or_else(|| format!("krpano document '{}' has no <image>", url))?;
+    Ok(image.level_descriptions())
+}
+
+/// Downloads the six faces of a krpano cube (one URL per [`CubeFace`]) and returns what should
+/// actually be saved: the six faces unchanged, or a single equirectangular panorama if the
+/// caller opted into `options.enabled` — see [`finalize_cube_faces`].
+pub async fn download_cube_faces(
+    urls: HashMap<CubeFace, String>,
+    options: &EquirectangularOptions,
+) -> Result<Vec<(&'static str, image::DynamicImage)>, String> {
+    let mut faces = HashMap::with_capacity(urls.len());
+    for (face, url) in urls {
+        let bytes = reqwest_client()
+            .get(&url)
+            .send().await
+            .map_err(|e| format!("could not download cube face '{}': {}", url, e))?
+            .bytes().await
+            .map_err(|e| format!("could not read cube face '{}': {}", url, e))?;
+        let decoded = image::load_from_memory(&bytes)
+            .map_err(|e| format!("could not decode cube face '{}': {}", url, e))?;
+        faces.insert(face, decoded);
+    }
+    Ok(finalize_cube_faces(faces, options))
+}